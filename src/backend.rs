@@ -0,0 +1,149 @@
+use std::pin::Pin;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use futures::{Sink, Stream};
+use tokio_tungstenite::tungstenite::Message as WSMessage;
+
+/// The write half of a gateway connection.
+///
+/// All backends funnel their native message type into [`WSMessage`] so the
+/// gateway loop can stay transport agnostic.
+#[cfg(not(target_arch = "wasm32"))]
+pub type GatewaySink = Pin<Box<dyn Sink<WSMessage, Error = anyhow::Error> + Send>>;
+/// The read half of a gateway connection.
+#[cfg(not(target_arch = "wasm32"))]
+pub type GatewayStream = Pin<Box<dyn Stream<Item = Result<WSMessage>> + Send>>;
+
+/// The write half of a gateway connection.
+#[cfg(target_arch = "wasm32")]
+pub type GatewaySink = Pin<Box<dyn Sink<WSMessage, Error = anyhow::Error>>>;
+/// The read half of a gateway connection.
+#[cfg(target_arch = "wasm32")]
+pub type GatewayStream = Pin<Box<dyn Stream<Item = Result<WSMessage>>>>;
+
+/// A pluggable WebSocket transport the gateway connects over.
+///
+/// Implementors open a socket and hand back its split write and read halves,
+/// letting the same heartbeat and reconnect logic run on every target.
+#[async_trait]
+pub trait GatewayBackend {
+    /// The write half returned by [`connect`](GatewayBackend::connect).
+    type Sink: Sink<WSMessage, Error = anyhow::Error> + Unpin;
+    /// The read half returned by [`connect`](GatewayBackend::connect).
+    type Stream: Stream<Item = Result<WSMessage>> + Unpin;
+
+    /// Open a connection to `url` and return its split write and read halves.
+    async fn connect(url: &str) -> Result<(Self::Sink, Self::Stream)>;
+}
+
+/// The transport used on the current target.
+#[cfg(not(target_arch = "wasm32"))]
+pub type Backend = native::NativeBackend;
+/// The transport used on the current target.
+#[cfg(target_arch = "wasm32")]
+pub type Backend = wasm::WasmBackend;
+
+#[cfg(not(target_arch = "wasm32"))]
+mod native {
+    use std::sync::Arc;
+
+    use anyhow::Result;
+    use futures::{SinkExt, StreamExt, TryStreamExt};
+    use tokio::net::TcpStream;
+    use tokio_tungstenite::{
+        connect_async_tls_with_config, tungstenite::Message as WSMessage, Connector, MaybeTlsStream,
+        WebSocketStream,
+    };
+
+    use async_trait::async_trait;
+
+    use super::{GatewayBackend, GatewaySink, GatewayStream};
+
+    type WsStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+    /// Native transport backed by `tokio-tungstenite` over `tokio`'s TCP stack.
+    pub struct NativeBackend;
+
+    #[async_trait]
+    impl GatewayBackend for NativeBackend {
+        type Sink = GatewaySink;
+        type Stream = GatewayStream;
+
+        async fn connect(url: &str) -> Result<(Self::Sink, Self::Stream)> {
+            let (socket, _) =
+                connect_async_tls_with_config(url, None, false, Some(connector()?)).await?;
+            Ok(split(socket))
+        }
+    }
+
+    /// Build a TLS connector trusting the platform's native root certificates.
+    fn connector() -> Result<Connector> {
+        let mut roots = rustls::RootCertStore::empty();
+        for cert in rustls_native_certs::load_native_certs()? {
+            roots.add(cert)?;
+        }
+        let config = rustls::ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        Ok(Connector::Rustls(Arc::new(config)))
+    }
+
+    /// Split a socket into boxed halves that surface [`anyhow`] errors.
+    fn split(socket: WsStream) -> (GatewaySink, GatewayStream) {
+        let (tx, rx) = socket.split();
+        let tx = tx.sink_map_err(anyhow::Error::from);
+        let rx = rx.map_err(anyhow::Error::from);
+        (Box::pin(tx), Box::pin(rx))
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+mod wasm {
+    use anyhow::{bail, Result};
+    use futures::{SinkExt, StreamExt};
+    use tokio_tungstenite::tungstenite::Message as WSMessage;
+    use ws_stream_wasm::{WsMessage, WsMeta};
+
+    use async_trait::async_trait;
+
+    use super::{GatewayBackend, GatewaySink, GatewayStream};
+
+    /// Browser/edge transport backed by `ws_stream_wasm`.
+    pub struct WasmBackend;
+
+    #[async_trait]
+    impl GatewayBackend for WasmBackend {
+        type Sink = GatewaySink;
+        type Stream = GatewayStream;
+
+        async fn connect(url: &str) -> Result<(Self::Sink, Self::Stream)> {
+            let (_meta, socket) = WsMeta::connect(url, None).await?;
+            let (tx, rx) = socket.split();
+            let tx = tx.with(|msg: WSMessage| async move { into_wasm(msg) });
+            let rx = rx.map(|msg| Ok(from_wasm(msg)));
+            Ok((Box::pin(tx), Box::pin(rx)))
+        }
+    }
+
+    /// Translate an outgoing [`WSMessage`] into its `ws_stream_wasm` equivalent.
+    ///
+    /// Browsers manage ping/pong and close frames themselves, so the client
+    /// never needs to send them; any such frame is rejected rather than
+    /// forwarded.
+    fn into_wasm(msg: WSMessage) -> Result<WsMessage> {
+        match msg {
+            WSMessage::Text(text) => Ok(WsMessage::Text(text)),
+            WSMessage::Binary(data) => Ok(WsMessage::Binary(data)),
+            msg => bail!("cannot forward {:?} frame over a browser socket", msg),
+        }
+    }
+
+    /// Translate an incoming `ws_stream_wasm` message into a [`WSMessage`].
+    fn from_wasm(msg: WsMessage) -> WSMessage {
+        match msg {
+            WsMessage::Text(text) => WSMessage::Text(text),
+            WsMessage::Binary(data) => WSMessage::Binary(data),
+        }
+    }
+}
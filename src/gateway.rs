@@ -2,23 +2,72 @@ use std::{
     collections::HashMap,
     pin::Pin,
     sync::Arc,
-    task::{Context, Poll, Waker},
-    thread,
+    task::{Context, Poll},
     time::Duration,
 };
 
 use anyhow::{bail, Result};
-use futures::{stream::SplitStream, SinkExt, Stream, StreamExt};
+use async_trait::async_trait;
+use futures::{SinkExt, Stream, StreamExt};
 use rand::{rngs::StdRng, Rng, SeedableRng};
 use todel::{ClientPayload, ServerPayload, User};
-use tokio::{net::TcpStream, sync::Mutex, task::JoinHandle, time};
-use tokio_tungstenite::{
-    connect_async, tungstenite::Message as WSMessage, MaybeTlsStream, WebSocketStream,
+use tokio::{
+    sync::{mpsc, Mutex},
+    task::JoinHandle,
+    time,
 };
+use tokio_tungstenite::tungstenite::Message as WSMessage;
 
-use crate::{models::Event, GATEWAY_URL};
+use crate::{
+    backend::{Backend, GatewayBackend, GatewaySink, GatewayStream},
+    models::{Event, EventKind},
+    GATEWAY_URL,
+};
+
+/// A cloneable handle for sending [`ClientPayload`]s to Pandemonium.
+///
+/// It owns the write half of the socket behind a shared lock so any number of
+/// tasks can push payloads at once; the receive task swaps in the new sink on
+/// reconnect so existing handles keep working.
+///
+/// Pandemonium's [`ClientPayload`] only carries `Ping` and `Authenticate`, so
+/// there is no typed presence/status setter: presence travels the other way,
+/// arriving as [`ServerPayload::PresenceUpdate`]. Any future client payload can
+/// still be pushed through [`send`](GatewayHandle::send).
+#[derive(Clone)]
+pub struct GatewayHandle {
+    tx: Arc<Mutex<Option<GatewaySink>>>,
+}
+
+impl std::fmt::Debug for GatewayHandle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("GatewayHandle").finish_non_exhaustive()
+    }
+}
+
+impl GatewayHandle {
+    fn new() -> Self {
+        Self {
+            tx: Arc::new(Mutex::new(None)),
+        }
+    }
 
-type WsReceiver = SplitStream<WebSocketStream<MaybeTlsStream<TcpStream>>>;
+    /// Install `sink` as the current write half, replacing any previous one.
+    async fn set(&self, sink: GatewaySink) {
+        *self.tx.lock().await = Some(sink);
+    }
+
+    /// Send a [`ClientPayload`] to the gateway.
+    pub async fn send(&self, payload: ClientPayload) -> Result<()> {
+        match self.tx.lock().await.as_mut() {
+            Some(tx) => {
+                tx.send(WSMessage::Text(serde_json::to_string(&payload)?))
+                    .await
+            }
+            None => bail!("Gateway is not connected"),
+        }
+    }
+}
 
 /// Data provided to the client from the gateway
 #[derive(Default, Debug, Clone)]
@@ -28,14 +77,48 @@ pub struct GatewayData {
 }
 
 /// A Stream of Pandemonium events
-#[derive(Debug)]
+///
+/// The socket is drained by a background task that decodes payloads, keeps the
+/// [`GatewayData`] cache up to date and forwards finished [`Event`]s over a
+/// channel; the [`Stream`] impl merely polls that channel's receiver.
 pub struct Events {
-    gateway_url: String,
-    token: String,
-    rx: Arc<Mutex<Option<WsReceiver>>>,
-    ping: Arc<Mutex<Option<JoinHandle<()>>>>,
-    rng: Arc<Mutex<StdRng>>,
-    data: Mutex<GatewayData>,
+    rx: mpsc::UnboundedReceiver<Event>,
+    handle: GatewayHandle,
+    observers: Vec<Arc<dyn EventObserver>>,
+}
+
+impl std::fmt::Debug for Events {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Events")
+            .field("observers", &self.observers.len())
+            .finish_non_exhaustive()
+    }
+}
+
+/// A consumer of gateway [`Event`]s registered through [`Events::subscribe`].
+///
+/// Observers let independent bot modules react to the same connection without
+/// fighting over the [`Stream`] impl's `next()`.
+#[async_trait]
+pub trait EventObserver: Send + Sync {
+    /// Called for every [`Event`] decoded from the gateway.
+    async fn on_event(&self, event: &Event);
+}
+
+/// An [`EventObserver`] that only forwards a single [`EventKind`] to its inner
+/// observer.
+struct Filtered {
+    kind: EventKind,
+    observer: Arc<dyn EventObserver>,
+}
+
+#[async_trait]
+impl EventObserver for Filtered {
+    async fn on_event(&self, event: &Event) {
+        if event.kind() == self.kind {
+            self.observer.on_event(event).await;
+        }
+    }
 }
 
 /// Simple gateway client
@@ -69,35 +152,86 @@ impl GatewayClient {
         self
     }
 
-    /// Start a connection to the Pandemonium and return [`Events`]
-    pub async fn get_events(&self) -> Result<Events> {
-        let mut events = Events::new(self.gateway_url.clone(), self.token.clone());
-        events.connect().await?;
-        Ok(events)
+    /// Start a connection to the Pandemonium and return the [`Events`] stream
+    /// together with a [`GatewayHandle`] for sending payloads back.
+    pub async fn get_events(&self) -> Result<(Events, GatewayHandle)> {
+        Events::connect(self.gateway_url.clone(), self.token.clone()).await
     }
 }
 
 impl Events {
-    fn new(gateway_url: String, token: String) -> Self {
-        Self {
+    /// The [`GatewayHandle`] backing this connection, for sending payloads
+    /// without holding onto the one returned from `get_events`.
+    pub fn handle(&self) -> &GatewayHandle {
+        &self.handle
+    }
+
+    /// Register an [`EventObserver`] to receive every event.
+    ///
+    /// Observers are dispatched to once the stream is driven by
+    /// [`dispatch`](Events::dispatch).
+    pub fn subscribe(&mut self, observer: Arc<dyn EventObserver>) {
+        self.observers.push(observer);
+    }
+
+    /// Register an [`EventObserver`] that only receives events of `kind`.
+    pub fn subscribe_kind(&mut self, kind: EventKind, observer: Arc<dyn EventObserver>) {
+        self.observers.push(Arc::new(Filtered { kind, observer }));
+    }
+
+    /// Drive the stream on a background task, fanning each decoded [`Event`] out
+    /// to every registered observer.
+    ///
+    /// This consumes the [`Events`]; use the [`Stream`] impl directly if you
+    /// prefer to pull events yourself.
+    pub fn dispatch(mut self) -> JoinHandle<()> {
+        let observers = std::mem::take(&mut self.observers);
+        tokio::spawn(async move {
+            let mut events = self;
+            while let Some(event) = events.next().await {
+                for observer in &observers {
+                    observer.on_event(&event).await;
+                }
+            }
+        })
+    }
+
+    /// Open the initial connection and spawn the receive task backing the
+    /// [`Stream`], returning it alongside a [`GatewayHandle`].
+    async fn connect(gateway_url: String, token: String) -> Result<(Events, GatewayHandle)> {
+        log::debug!("Events connecting");
+        let handle = GatewayHandle::new();
+        let rng = Arc::new(Mutex::new(StdRng::from_entropy()));
+        let (socket, heartbeat_interval) =
+            Self::authenticate(&gateway_url, &token, &handle, &rng).await?;
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(Self::run(
             gateway_url,
             token,
-            rx: Arc::new(Mutex::new(None)),
-            ping: Arc::new(Mutex::new(None)),
-            rng: Arc::new(Mutex::new(StdRng::from_entropy())),
-            // FIXME: send self to hell and make not mutex thanks
-            data: Mutex::new(GatewayData::default()),
-        }
+            handle.clone(),
+            rng,
+            tx,
+            socket,
+            heartbeat_interval,
+        ));
+        let events = Events {
+            rx,
+            handle: handle.clone(),
+            observers: Vec::new(),
+        };
+        Ok((events, handle))
     }
 
-    async fn connect(&mut self) -> Result<()> {
-        log::debug!("Events connecting");
-        let mut ping = self.ping.lock().await;
-        if ping.is_some() {
-            ping.as_mut().unwrap().abort();
-        }
-        let (socket, _) = connect_async(&self.gateway_url).await?;
-        let (mut tx, mut rx) = socket.split();
+    /// Perform a single connect + authenticate handshake, installing the new
+    /// sink into `handle` and returning the read half and heartbeat interval.
+    async fn authenticate(
+        gateway_url: &str,
+        token: &str,
+        handle: &GatewayHandle,
+        rng: &Mutex<StdRng>,
+    ) -> Result<(GatewayStream, u64)> {
+        let (tx, mut rx) = Backend::connect(gateway_url).await?;
+        handle.set(tx).await;
         loop {
             if let Some(Ok(WSMessage::Text(msg))) = rx.next().await {
                 if let Ok(ServerPayload::Hello {
@@ -105,122 +239,94 @@ impl Events {
                 }) = serde_json::from_str(&msg)
                 {
                     time::sleep(Duration::from_millis(
-                        self.rng.lock().await.gen_range(0..heartbeat_interval),
+                        rng.lock().await.gen_range(0..heartbeat_interval),
                     ))
                     .await;
-                    if let Err(err) = tx
-                        .send(WSMessage::Text(
-                            serde_json::to_string(&ClientPayload::Authenticate(self.token.clone()))
-                                .unwrap(),
-                        ))
-                        .await
-                    {
-                        bail!("Encountered error while authenticating {:?}", err);
-                    };
-                    *ping = Some(tokio::spawn(async move {
-                        loop {
-                            match tx
-                                .send(WSMessage::Text(
-                                    serde_json::to_string(&ClientPayload::Ping).unwrap(),
-                                ))
-                                .await
-                            {
-                                Ok(_) => {
-                                    time::sleep(Duration::from_millis(heartbeat_interval)).await
-                                }
-                                Err(err) => {
-                                    log::debug!("Encountered error while pinging {:?}", err);
-                                    break;
-                                }
-                            }
-                        }
-                    }));
-                    break;
+                    handle
+                        .send(ClientPayload::Authenticate(token.to_string()))
+                        .await?;
+                    return Ok((rx, heartbeat_interval));
                 }
             } else {
                 bail!("Could not find HELLO payload");
             }
         }
-
-        *self.rx.lock().await = Some(rx);
-        Ok(())
     }
 
-    async fn reconect(
-        waker: Waker,
+    /// The receive task: decode payloads, keep the cache current, forward
+    /// [`Event`]s over `tx` and transparently reconnect on disconnect.
+    async fn run(
         gateway_url: String,
         token: String,
-        rx: Arc<Mutex<Option<WsReceiver>>>,
-        ping: Arc<Mutex<Option<JoinHandle<()>>>>,
+        handle: GatewayHandle,
         rng: Arc<Mutex<StdRng>>,
+        tx: mpsc::UnboundedSender<Event>,
+        mut rx: GatewayStream,
+        mut heartbeat_interval: u64,
     ) {
-        let mut wait = 1;
-        let mut ping = ping.lock().await;
-        if ping.is_some() {
-            ping.as_mut().unwrap().abort();
-        }
-        'outer: loop {
-            match connect_async(&gateway_url).await {
-                Ok((socket, _)) => {
-                    let (mut tx, mut new_rx) = socket.split();
-                    loop {
-                        if let Some(Ok(WSMessage::Text(msg))) = new_rx.next().await {
-                            if let Ok(ServerPayload::Hello {
-                                heartbeat_interval, ..
-                            }) = serde_json::from_str(&msg)
-                            {
-                                if let Err(err) = tx
-                                    .send(WSMessage::Text(
-                                        serde_json::to_string(&ClientPayload::Authenticate(
-                                            token.clone(),
-                                        ))
-                                        .unwrap(),
-                                    ))
-                                    .await
-                                {
-                                    log::error!("Encountered error while authenticating {:?}", err);
-                                    continue;
-                                };
-                                *ping = Some(tokio::spawn(async move {
-                                    time::sleep(Duration::from_millis(
-                                        rng.lock().await.gen_range(0..heartbeat_interval),
-                                    ))
-                                    .await;
-                                    loop {
-                                        match tx
-                                            .send(WSMessage::Text(
-                                                serde_json::to_string(&ClientPayload::Ping)
-                                                    .unwrap(),
-                                            ))
-                                            .await
-                                        {
-                                            Ok(_) => {
-                                                time::sleep(Duration::from_millis(
-                                                    heartbeat_interval,
-                                                ))
-                                                .await
-                                            }
-                                            Err(err) => {
-                                                log::debug!(
-                                                    "Encountered error while pinging {:?}",
-                                                    err
-                                                );
-                                                break;
-                                            }
-                                        }
-                                    }
-                                }));
+        let mut data = GatewayData::default();
+        let mut ping = Some(Self::spawn_heartbeat(handle.clone(), heartbeat_interval));
+        loop {
+            match rx.next().await {
+                Some(Ok(WSMessage::Text(msg))) => {
+                    if let Ok(payload) = serde_json::from_str(&msg) {
+                        if let Some(event) = Self::decode(payload, &mut data) {
+                            if tx.send(event).is_err() {
                                 break;
                             }
-                        } else {
-                            log::error!("Could not find HELLO payload");
-                            continue 'outer;
                         }
                     }
+                    continue;
+                }
+                Some(Ok(WSMessage::Close(_))) | None => {
+                    log::debug!("Websocket closed, reconnecting")
+                }
+                Some(Ok(_)) => continue,
+                Some(Err(err)) => log::debug!("Websocket error {:?}, reconnecting", err),
+            }
+            if let Some(ping) = ping.take() {
+                ping.abort();
+            }
+            let (socket, interval) = Self::reconnect(&gateway_url, &token, &handle, &rng).await;
+            rx = socket;
+            heartbeat_interval = interval;
+            ping = Some(Self::spawn_heartbeat(handle.clone(), heartbeat_interval));
+        }
+        if let Some(ping) = ping.take() {
+            ping.abort();
+        }
+    }
 
-                    *rx.lock().await = Some(new_rx);
+    /// Spawn the heartbeat task, pinging every `heartbeat_interval` ms until the
+    /// socket errors.
+    fn spawn_heartbeat(handle: GatewayHandle, heartbeat_interval: u64) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            loop {
+                match handle.send(ClientPayload::Ping).await {
+                    Ok(_) => time::sleep(Duration::from_millis(heartbeat_interval)).await,
+                    Err(err) => {
+                        log::debug!("Encountered error while pinging {:?}", err);
+                        break;
+                    }
+                }
+            }
+        })
+    }
+
+    /// Retry [`authenticate`](Events::authenticate) with exponential backoff
+    /// until the connection is re-established.
+    async fn reconnect(
+        gateway_url: &str,
+        token: &str,
+        handle: &GatewayHandle,
+        rng: &Mutex<StdRng>,
+    ) -> (GatewayStream, u64) {
+        let mut wait = 1;
+        loop {
+            match Self::authenticate(gateway_url, token, handle, rng).await {
+                Ok(connection) => {
                     log::debug!("Reconnected to websocket");
-                    break;
+                    break connection;
                 }
                 Err(err) => {
                     log::info!(
@@ -228,14 +334,45 @@ impl Events {
                         err,
                         wait
                     );
-                    thread::sleep(Duration::from_secs(wait));
+                    time::sleep(Duration::from_secs(wait)).await;
                     if wait < 64 {
                         wait *= 2;
                     }
                 }
             }
         }
-        waker.wake();
+    }
+
+    /// Decode a [`ServerPayload`] into an [`Event`], updating the cache.
+    fn decode(payload: ServerPayload, data: &mut GatewayData) -> Option<Event> {
+        match payload {
+            ServerPayload::Pong
+            | ServerPayload::RateLimit { .. }
+            | ServerPayload::Hello { .. } => None,
+            ServerPayload::Authenticated { user, users } => {
+                data.user = Some(user);
+                users.into_iter().for_each(|u| {
+                    data.users.insert(u.id, u);
+                });
+                Some(Event::Authenticated)
+            }
+            ServerPayload::MessageCreate(msg) => Some(Event::Message(msg)),
+            ServerPayload::UserUpdate(update) => {
+                let user = data.users.insert(update.id, update.clone());
+                Some(Event::UserUpdate {
+                    old_user: user,
+                    user: update,
+                })
+            }
+            ServerPayload::PresenceUpdate { status, user_id } => {
+                let user = data.users.get(&user_id);
+                Some(Event::PresenceUpdate {
+                    old_status: user.map(|u| u.status.clone()),
+                    user_id,
+                    status,
+                })
+            }
+        }
     }
 }
 
@@ -243,76 +380,6 @@ impl Stream for Events {
     type Item = Event;
 
     fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        loop {
-            let mut data = futures::executor::block_on(async { self.data.lock().await });
-            let mut rx = futures::executor::block_on(async { self.rx.lock().await });
-            if rx.is_some() {
-                match rx.as_mut().unwrap().poll_next_unpin(cx) {
-                    Poll::Ready(Some(Ok(msg))) => match msg {
-                        WSMessage::Text(msg) => {
-                            if let Ok(payload) = serde_json::from_str(&msg) {
-                                match payload {
-                                    ServerPayload::Pong
-                                    | ServerPayload::RateLimit { .. }
-                                    | ServerPayload::Hello { .. } => {}
-                                    ServerPayload::Authenticated { user, users } => {
-                                        data.user = Some(user);
-                                        users.into_iter().for_each(|u| {
-                                            data.users.insert(u.id, u);
-                                        });
-                                        break Poll::Ready(Some(Event::Authenticated));
-                                    }
-                                    ServerPayload::MessageCreate(msg) => {
-                                        break Poll::Ready(Some(Event::Message(msg)));
-                                    }
-                                    ServerPayload::UserUpdate(update) => {
-                                        let user = data.users.insert(update.id, update.clone());
-                                        break Poll::Ready(Some(Event::UserUpdate {
-                                            old_user: user,
-                                            user: update,
-                                        }));
-                                    }
-                                    ServerPayload::PresenceUpdate { status, user_id } => {
-                                        let user = data.users.get(&user_id);
-                                        break Poll::Ready(Some(Event::PresenceUpdate {
-                                            old_status: user.map(|u| u.status.clone()),
-                                            user_id,
-                                            status,
-                                        }));
-                                    }
-                                }
-                            }
-                        }
-                        WSMessage::Close(_) => {
-                            log::debug!("Websocket closed, reconnecting");
-                            tokio::spawn(Events::reconect(
-                                cx.waker().clone(),
-                                self.gateway_url.clone(),
-                                self.token.clone(),
-                                Arc::clone(&self.rx),
-                                Arc::clone(&self.ping),
-                                Arc::clone(&self.rng),
-                            ));
-                            return Poll::Pending;
-                        }
-                        _ => {}
-                    },
-                    Poll::Pending => break Poll::Pending,
-                    Poll::Ready(None) => {
-                        log::debug!("Websocket closed, reconnecting");
-                        tokio::spawn(Events::reconect(
-                            cx.waker().clone(),
-                            self.gateway_url.clone(),
-                            self.token.clone(),
-                            Arc::clone(&self.rx),
-                            Arc::clone(&self.ping),
-                            Arc::clone(&self.rng),
-                        ));
-                        return Poll::Pending;
-                    }
-                    _ => {}
-                }
-            }
-        }
+        self.get_mut().rx.poll_recv(cx)
     }
 }
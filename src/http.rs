@@ -1,15 +1,20 @@
-use crate::{models::MessageResponse, GatewayClient, REST_URL};
+use crate::{
+    models::ApiResponse,
+    ratelimit::{LimitType, RateLimiter},
+    GatewayClient, REST_URL,
+};
 use anyhow::Result;
-use reqwest::Client;
-use std::{fmt::Display, time::Duration};
+use reqwest::{Client, RequestBuilder};
+use serde::de::DeserializeOwned;
+use std::{fmt::Display, sync::Arc};
 use todel::{ErrorResponse, InstanceInfo, Message, MessageCreate};
-use tokio::time;
 
 /// Simple Http client
 #[derive(Debug, Clone)]
 pub struct HttpClient {
     client: Client,
     instance_info: Option<InstanceInfo>,
+    ratelimiter: Arc<RateLimiter>,
     token: String,
     pub rest_url: String,
 }
@@ -20,11 +25,57 @@ impl HttpClient {
         HttpClient {
             client: Client::new(),
             instance_info: None,
+            ratelimiter: Arc::new(RateLimiter::default()),
             token: token.to_string(),
             rest_url: REST_URL.to_string(),
         }
     }
 
+    /// Perform a request against a rate-limited route.
+    ///
+    /// The `builder` closure is invoked once per attempt so the request can be
+    /// rebuilt on retry. Before sending, both the global and the route's bucket
+    /// are honoured by sleeping until their reset if exhausted; a
+    /// [`RateLimited`] response exhausts both buckets (eludris does not tell us
+    /// which one was hit) and the request is retried transparently.
+    ///
+    /// [`RateLimited`]: todel::ErrorResponse::RateLimited
+    async fn request<T, F>(&self, limit_type: LimitType, builder: F) -> Result<T>
+    where
+        T: DeserializeOwned,
+        F: Fn() -> RequestBuilder,
+    {
+        loop {
+            self.ratelimiter.acquire(limit_type).await;
+            match builder().send().await?.json::<ApiResponse<T>>().await? {
+                ApiResponse::Success(value) => break Ok(value),
+                ApiResponse::Error(ErrorResponse::RateLimited { retry_after, .. }) => {
+                    log::info!(
+                        "Client got ratelimited on the {:?} bucket, retrying in {}ms",
+                        limit_type,
+                        retry_after
+                    );
+                    self.ratelimiter
+                        .exhaust(LimitType::Global, retry_after)
+                        .await;
+                    self.ratelimiter.exhaust(limit_type, retry_after).await;
+                }
+                ApiResponse::Error(ErrorResponse::Validation {
+                    value_name, info, ..
+                }) => {
+                    break Err(anyhow::anyhow!(
+                        "Ran into a validation error with field {}: {}",
+                        value_name,
+                        info,
+                    ));
+                }
+                ApiResponse::Error(err) => {
+                    break Err(anyhow::anyhow!("Request failed: {:?}", err));
+                }
+            }
+        }
+    }
+
     /// Change the url of the HttpClient
     ///
     /// # Example:
@@ -57,47 +108,17 @@ impl HttpClient {
 
     /// Send a message
     pub async fn send_message<C: Display>(&self, content: C) -> Result<Message> {
-        loop {
-            match self
-                .client
+        let content = content.to_string();
+        self.request(LimitType::Messages, || {
+            self.client
                 .post(format!("{}/messages", self.rest_url))
                 .header("Authorization", &self.token)
                 .json(&MessageCreate {
-                    content: content.to_string(),
+                    content: content.clone(),
                     disguise: None,
                 })
-                .send()
-                .await?
-                .json::<MessageResponse>()
-                .await
-            {
-                Ok(MessageResponse::Message(msg)) => {
-                    break Ok(msg);
-                }
-                Ok(MessageResponse::Error(err)) => match err {
-                    ErrorResponse::RateLimited { retry_after, .. } => {
-                        log::info!(
-                            "Client got ratelimited at /messages, retrying in {}ms",
-                            retry_after
-                        );
-                        time::sleep(Duration::from_millis(retry_after)).await;
-                    }
-                    ErrorResponse::Validation {
-                        value_name, info, ..
-                    } => {
-                        Err(anyhow::anyhow!(
-                            "Ran into a validation error with field {}: {}",
-                            value_name,
-                            info,
-                        ))?;
-                    }
-                    err => Err(anyhow::anyhow!("Could not send message: {:?}", err))?,
-                },
-                Err(err) => {
-                    break Err(err)?;
-                }
-            }
-        }
+        })
+        .await
     }
 
     /// Create a [`GatewayClient`] using the connected instance's instance info
@@ -27,7 +27,7 @@
 //! # async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
 //! let mut http = HttpClient::new().name("Uwuki".to_string());
 //! let gateway = http.create_gateway().await?; // uses the InstanceInfo of the instance
-//! let mut events = gateway.get_events().await.unwrap();
+//! let (mut events, _handle) = gateway.get_events().await.unwrap();
 //!
 //! while let Some(msg) = events.next().await {
 //!     if msg.content == "!ping" {
@@ -46,12 +46,16 @@
 //! ```shell
 //! cargo doc -p eludrs --open
 //! ```
+mod backend;
 mod gateway;
 mod http;
 mod models;
+mod ratelimit;
 
-pub use gateway::{Events, GatewayClient};
+pub use backend::GatewayBackend;
+pub use gateway::{Events, EventObserver, GatewayClient, GatewayHandle};
 pub use http::HttpClient;
+pub use models::{Event, EventKind};
 
 /// All the todel models re-exported
 pub mod todel {
@@ -1,13 +1,28 @@
 use serde::{Deserialize, Serialize};
 use todel::{ErrorResponse, Message, Status, User};
 
+/// The two shapes an Eludris REST route can respond with: the expected payload
+/// or an [`ErrorResponse`].
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(untagged)]
-pub(crate) enum MessageResponse {
-    Message(Message),
+pub(crate) enum ApiResponse<T> {
+    Success(T),
     Error(ErrorResponse),
 }
 
+/// The kind of an [`Event`], used to filter observer subscriptions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventKind {
+    /// See [`Event::Authenticated`].
+    Authenticated,
+    /// See [`Event::Message`].
+    Message,
+    /// See [`Event::UserUpdate`].
+    UserUpdate,
+    /// See [`Event::PresenceUpdate`].
+    PresenceUpdate,
+}
+
 /// An abstraction over gateway event types
 #[derive(Debug, Clone)]
 pub enum Event {
@@ -24,3 +39,15 @@ pub enum Event {
         status: Status,
     },
 }
+
+impl Event {
+    /// The [`EventKind`] this event belongs to.
+    pub fn kind(&self) -> EventKind {
+        match self {
+            Event::Authenticated => EventKind::Authenticated,
+            Event::Message(_) => EventKind::Message,
+            Event::UserUpdate { .. } => EventKind::UserUpdate,
+            Event::PresenceUpdate { .. } => EventKind::PresenceUpdate,
+        }
+    }
+}
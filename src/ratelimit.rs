@@ -0,0 +1,71 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use tokio::{sync::Mutex, time};
+
+/// The bucket a request is accounted against.
+///
+/// [`Global`](LimitType::Global) covers instance-wide limits while the
+/// remaining variants map to individual routes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum LimitType {
+    /// The instance-wide limit shared by every route.
+    Global,
+    /// The `/messages` route.
+    Messages,
+}
+
+/// The tracked state of a single [`LimitType`] bucket.
+///
+/// Eludris' REST API does not surface remaining quota or a reset timestamp on
+/// successful responses, so the only thing worth tracking is when a bucket that
+/// has already been hit frees up again.
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    reset_at: Instant,
+}
+
+/// Tracks per-route rate-limit buckets shared across all REST routes.
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    buckets: Mutex<HashMap<LimitType, Bucket>>,
+}
+
+impl RateLimiter {
+    /// Wait until both the [`Global`](LimitType::Global) bucket and `limit`'s
+    /// own bucket have freed up, sleeping until the latest of their resets.
+    pub async fn acquire(&self, limit: LimitType) {
+        let sleep = {
+            let now = Instant::now();
+            let buckets = self.buckets.lock().await;
+            [LimitType::Global, limit]
+                .iter()
+                .filter_map(|ty| buckets.get(ty))
+                .filter_map(|bucket| bucket.reset_at.checked_duration_since(now))
+                .max()
+        };
+        if let Some(duration) = sleep {
+            log::debug!(
+                "Pre-emptively sleeping {}ms for exhausted {:?} bucket",
+                duration.as_millis(),
+                limit
+            );
+            time::sleep(duration).await;
+            let mut buckets = self.buckets.lock().await;
+            buckets.remove(&LimitType::Global);
+            buckets.remove(&limit);
+        }
+    }
+
+    /// Mark `limit` as exhausted until `retry_after` milliseconds from now.
+    pub async fn exhaust(&self, limit: LimitType, retry_after: u64) {
+        self.buckets.lock().await.insert(
+            limit,
+            Bucket {
+                reset_at: Instant::now() + Duration::from_millis(retry_after),
+            },
+        );
+    }
+}